@@ -901,6 +901,25 @@ impl<'a> StringReader<'a> {
     /// token, and updates the interner
     fn next_token_inner(&mut self) -> token::Token {
         let c = self.curr;
+        // Raw identifiers, like `r#match`, are never keywords. They keep their `r#`,
+        // so that they are printed back out the way they were written.
+        if c == Some('r') && self.nextch_is('#') && ident_start(self.nextnextch()) {
+            let start = self.last_pos;
+            self.bump();
+            self.bump();
+            while ident_continue(self.curr) {
+                self.bump();
+            }
+
+            return self.with_str_from(start, |string| {
+                if self.curr_is(':') && self.nextch_is(':') {
+                    token::Ident(str_to_ident(string), token::ModName)
+                } else {
+                    token::Ident(str_to_ident(string), token::Plain)
+                }
+            });
+        }
+
         if ident_start(c) && match (c.unwrap(), self.nextch(), self.nextnextch()) {
             // Note: r as in r" or r#" is part of a raw string literal,
             // b as in b' is part of a byte literal.
@@ -947,6 +966,9 @@ impl<'a> StringReader<'a> {
                   if self.curr_is('.') {
                       self.bump();
                       token::DotDotDot
+                  } else if self.curr_is('=') {
+                      self.bump();
+                      token::DotDotEq
                   } else {
                       token::DotDot
                   }
@@ -1296,7 +1318,7 @@ impl<'a> StringReader<'a> {
         }
         let id = if valid { self.name_from(start) } else { token::intern("??") };
         self.bump();
-        return token::Binary(id);
+        return token::ByteStr(id);
     }
 
     fn scan_raw_byte_string(&mut self) -> token::Lit {
@@ -1347,7 +1369,7 @@ impl<'a> StringReader<'a> {
             self.bump();
         }
         self.bump();
-        return token::BinaryRaw(self.name_from_to(content_start_bpos,
+        return token::ByteStrRaw(self.name_from_to(content_start_bpos,
                                                   content_end_bpos),
                                 hash_count);
     }
@@ -1410,6 +1432,7 @@ mod tests {
     use diagnostic;
     use parse::token;
     use parse::token::{str_to_ident};
+    use print::pprust;
     use std::io;
 
     fn mk_sh() -> diagnostic::SpanHandler {
@@ -1522,6 +1545,27 @@ mod tests {
                    token::Literal(token::StrRaw(token::intern("\"#a\\b\x00c\""), 3), None));
     }
 
+    #[test] fn raw_idents() {
+        check_tokenization(setup(&mk_sh(), "r#match".to_string()),
+                           vec![mk_ident("r#match", token::Plain)]);
+        check_tokenization(setup(&mk_sh(), "r#in::x".to_string()),
+                           vec![mk_ident("r#in", token::ModName),
+                                token::ModSep,
+                                mk_ident("x", token::Plain)]);
+        // `r#"` still starts a raw string
+        assert_eq!(setup(&mk_sh(), "r#\"raw\"#".to_string()).next_token().tok,
+                   token::Literal(token::StrRaw(token::intern("raw"), 1), None));
+        assert_eq!(pprust::token_to_string(&mk_ident("r#match", token::Plain)), "r#match");
+    }
+
+    #[test] fn dot_dot_eq() {
+        check_tokenization(setup(&mk_sh(), "a..=b".to_string()),
+                           vec![mk_ident("a", token::Plain),
+                                token::DotDotEq,
+                                mk_ident("b", token::Plain)]);
+        assert_eq!(pprust::token_to_string(&token::DotDotEq), "..=");
+    }
+
     #[test] fn literal_suffixes() {
         macro_rules! test {
             ($input: expr, $tok_type: ident, $tok_contents: expr) => {{
@@ -1538,7 +1582,7 @@ mod tests {
         test!("'a'", Char, "a");
         test!("b'a'", Byte, "a");
         test!("\"a\"", Str_, "a");
-        test!("b\"a\"", Binary, "a");
+        test!("b\"a\"", ByteStr, "a");
         test!("1234", Integer, "1234");
         test!("0b101", Integer, "0b101");
         test!("0xABC", Integer, "0xABC");
@@ -1552,7 +1596,7 @@ mod tests {
                    token::Literal(token::StrRaw(token::intern("raw"), 3),
                                   Some(token::intern("suffix"))));
         assert_eq!(setup(&mk_sh(), "br###\"raw\"###suffix".to_string()).next_token().tok,
-                   token::Literal(token::BinaryRaw(token::intern("raw"), 3),
+                   token::Literal(token::ByteStrRaw(token::intern("raw"), 3),
                                   Some(token::intern("suffix"))));
     }
 
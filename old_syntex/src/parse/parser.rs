@@ -1514,9 +1514,9 @@ impl<'a> Parser<'a> {
                             token::intern_and_get_ident(&parse::raw_str_lit(s.as_str())),
                             ast::RawStr(n)))
                     }
-                    token::Binary(i) =>
+                    token::ByteStr(i) =>
                         (true, LitBinary(parse::binary_lit(i.as_str()))),
-                    token::BinaryRaw(i, _) =>
+                    token::ByteStrRaw(i, _) =>
                         (true,
                          LitBinary(Rc::new(i.as_str().as_bytes().iter().cloned().collect()))),
                 };
@@ -1,54 +1,864 @@
 extern crate syntex_syntax;
 extern crate docopt;
+mod width;
 use std::usize;
+use std::cmp;
+use std::u32;
+use std::vec;
+use std::iter;
+use std::iter::Peekable;
+use std::rc::Rc;
 use std::path::Path;
 use std::fs::File;
+use std::io;
 use std::io::Write;
+use std::process;
 use syntex_syntax::parse;
 use syntex_syntax::print::pprust;
-use syntex_syntax::ast::{TokenTree, TtToken, TtDelimited, TtSequence};
-use syntex_syntax::parse::token::{Token, DelimToken, IdentStyle};
-use syntex_syntax::codemap::Span;
+use syntex_syntax::ast::{TokenTree, TtToken, TtDelimited, TtSequence, Delimited};
+use syntex_syntax::parse::token::{Token, DelimToken, BinOpToken, IdentStyle, Lit, keywords, str_to_ident};
+use syntex_syntax::parse::lexer::comments;
+use syntex_syntax::codemap::{Span, BytePos, CharPos, mk_sp};
 use docopt::Docopt;
-static USAGE: &'static str = "
-Usage: slag <source> [-o OUTPUT]
+use width::char_width;
+static USAGE: &'static str = "Usage: slag <source> [-o OUTPUT] [--opener OPENER] [--tolerant | --fix]
 
 Options:
-    -o OUTPUT  The output file to emit source to
+    -o OUTPUT          The output file to emit source to
+    --opener OPENER    The token which opens blocks: =>, : or do [default: =>]
+    --tolerant         Accept braces and semicolons which repeat the layout, with a warning
+    --fix              Like --tolerant, but also remove them from the source
 ";
 fn main() {
+    // Get the arguments from the input stram
     let args = Docopt::new(USAGE)
         .and_then(|d| d.argv(std::env::args()).parse())
         .unwrap_or_else(|e| e.exit());
     let source = args.get_str("<source>");
     let mut dest = args.get_str("-o").to_string();
+    // Like docopt does for bad arguments, a bad opener is a usage error
+    let opener = opener_from_str(args.get_str("--opener")).unwrap_or_else(|| {
+        writeln!(io::stderr(), "Unknown block opener `{}`, expected one of =>, : or do\n{}",
+                 args.get_str("--opener"), USAGE.lines().next().unwrap()).unwrap();
+        process::exit(1) }
+    );
+    // Parse the input into a set of token trees
     let psess = parse::ParseSess::new();
-    let mut parser = parse::new_parser_from_file(&psess,
-                                                 Vec::new(),
-                                                 Path::new(source));
+    let mut parser = parse::new_parser_from_file(
+        &psess,
+        Vec::new(),
+        Path::new(source)
+    );
     let tts = parser.parse_all_token_trees().unwrap();
+    // Pragmas at the top of the file override the command line
+    let mut config = Config {
+        opener: opener,
+        indent: None,
+        tabs: Tabs::Allow,
+        tab_width: 4,
+        mixed: Mixed::Normalize };
+    let tts = read_pragmas(&psess, &mut config, &tts);
+    psess.span_diagnostic.handler().abort_if_errors();
+    // Braces and semicolons left over from writing Rust are replaced with the layout
+    let fix = args.get_bool("--fix");
+    let mut fixes = Vec::new();
+    let tts = if fix || args.get_bool("--tolerant") {
+        migrate_tts(&psess, config, &tts, false, &mut fixes) }
+    else {
+        tts };
+    // The token trees don't contain comments, so gather them separately
+    let mut comment_src = File::open(Path::new(source)).unwrap();
+    let (comments, _) = comments::gather_comments_and_literals(&psess.span_diagnostic,
+                                                               source.to_string(),
+                                                               &mut comment_src);
+    let mut comments = comments.into_iter().peekable();
+    // Open the output file
     if dest == "" {
         dest = format!("{}.rs", source.trim_right_matches(".slag")) };
     let mut file = File::create(Path::new(&dest)).unwrap();
-    handle_tts(&psess, &mut (usize::MAX, 0), &mut file, &tts) }
+    // Run the syntax transformer
+    let mut last_pos = (usize::MAX, 0);
+    handle_tts(&psess, &mut last_pos, &mut file, &mut comments, config, &tts,
+               (0, BlockFlag::Module));
+    print_comments(&psess, config, &mut last_pos, &mut file, &mut comments, BytePos(u32::MAX));
+    psess.span_diagnostic.handler().abort_if_errors();
+    // The source is only rewritten once it is known to be fine
+    if fix {
+        apply_fixes(&psess, source, fixes) } }
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum BlockFlag {
     None,
     Module,
     Match,
-    EnumStruct }
-fn ends_from_span(psess: &parse::ParseSess, span: Span) -> (usize, usize, usize, usize) {
+    EnumStruct,
+    Impl,
+    Trait,
+    Extern,
+    // The contents of a pair of delimiters, where new lines don't get separators
+    Delimited,
+    // The fields of a struct literal, opened with `Path =>`
+    StructLit,
+    // The elements of a list, opened with `(=>` or `[=>`
+    List,
+    // The body of a function, which is otherwise laid out like `None`
+    Fn,
+    // The rules of a `macro_rules!` definition
+    MacroRules,
+    // Token trees which are written out exactly as they are, like macro patterns
+    Verbatim,
+    // The paths imported by a `use` item, which are written out as a use tree
+    Use,
+    // The body of a block-style macro invocation, opened with `name! =>`. It is laid
+    // out like a module, but is part of an expression or statement.
+    Macro }
+// The token which opens a block. Match arms and macro rules are always opened by
+// `=>`, so with the other openers it isn't used for anything else.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Opener {
+    FatArrow,
+    // A `:` at the end of a line
+    Colon,
+    // The `do` keyword
+    Do }
+fn opener_from_str(opener: &str) -> Option<Opener> {
+    match opener {
+        "=>" => { Some(Opener::FatArrow) },
+        ":" => { Some(Opener::Colon) },
+        "do" => { Some(Opener::Do) },
+        _ => { None } } }
+fn opener_to_str(opener: Opener) -> &'static str {
+    match opener {
+        Opener::FatArrow => { "=>" },
+        Opener::Colon => { ":" },
+        Opener::Do => { "do" } } }
+fn opener_to_token(opener: Opener) -> Token {
+    match opener {
+        Opener::FatArrow => { Token::FatArrow },
+        Opener::Colon => { Token::Colon },
+        Opener::Do => { Token::Ident(str_to_ident("do"), IdentStyle::Plain) } } }
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Tabs {
+    // Tabs go to the next tab stop
+    Allow,
+    // Tabs in indentation are reported as errors
+    Error }
+fn tabs_from_str(tabs: &str) -> Option<Tabs> {
+    match tabs {
+        "allow" => { Some(Tabs::Allow) },
+        "error" => { Some(Tabs::Error) },
+        _ => { None } } }
+// What to do with lines which are indented with tabs where the line before them
+// is indented with spaces, or the other way around
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Mixed {
+    Reject,
+    Warn,
+    // Tabs are expanded to the next tab stop, and the line is used as it is
+    Normalize }
+fn mixed_from_str(mixed: &str) -> Option<Mixed> {
+    match mixed {
+        "reject" => { Some(Mixed::Reject) },
+        "warn" => { Some(Mixed::Warn) },
+        "normalize" => { Some(Mixed::Normalize) },
+        _ => { None } } }
+// Settings which a file can change with `#![slag(key = value, ...)]`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Config {
+    opener: Opener,
+    // The number of columns each block is indented by, if it is checked
+    indent: Option<usize>,
+    tabs: Tabs,
+    // The number of columns between tab stops
+    tab_width: usize,
+    mixed: Mixed }
+// What a `:` at the end of a line is, when `:` is the opener. Types and bounds can
+// be split over several lines after their `:`, so it only opens a block at the level
+// of an item or statement.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum ColonState {
+    // It opens a block
+    Opener,
+    // It comes before the type of a `let`
+    LetType,
+    // It comes before the bounds of a predicate in a `where` clause
+    WhereBounds,
+    // It comes after the bounds of a predicate in a `where` clause, so it opens the
+    // body of the item
+    Where }
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum AttrState {
+    None,
+    // We have seen the `#` or `#!` which starts an attribute
+    Start,
+    // The last token tree ended an attribute or doc comment
+    End }
+type Comments = Peekable<vec::IntoIter<comments::Comment>>;
+// Items in modules, impls, traits and extern blocks which don't end with a block need a semicolon,
+// even when they are the last item before the block is closed. `item` is the token trees of
+// the item so far.
+fn needs_item_semi(config: Config,
+                   block_flag: BlockFlag,
+                   prev_tt: Option<&TokenTree>,
+                   item: &[TokenTree],
+                   attr_state: AttrState) -> bool {
+    match block_flag {
+        BlockFlag::Module | BlockFlag::Impl | BlockFlag::Trait | BlockFlag::Extern |
+            BlockFlag::Macro => {
+            match prev_tt {
+                Some(&TtDelimited(_, ref delimited))
+                    if delimited.delim == DelimToken::Brace && is_braced_body(item) => { false },
+                // The block is empty, or the last line already has a semicolon. A line can
+                // only end in the opener if it opened a block.
+                Some(&TtToken(_, Token::Semi)) => { false },
+                Some(&TtToken(_, ref tok)) if is_opener_token(config.opener, tok) => { false },
+                Some(_) => { attr_state != AttrState::End },
+                None => { false } } },
+        _ => { false } } }
+// An item whose value is laid out as a block, like a `const` set to a struct literal,
+// still needs a semicolon once the block is closed
+fn needs_value_semi(block_flag: BlockFlag, closed_flag: BlockFlag, item: &[TokenTree]) -> bool {
+    match block_flag {
+        BlockFlag::Module | BlockFlag::Impl | BlockFlag::Trait | BlockFlag::Extern |
+            BlockFlag::Macro => { is_value_block(closed_flag) && !is_braced_body(item) },
+        _ => { false } } }
+// Whether the braces at the end of an item are its body, like in `struct Foo { x: i32 }`,
+// rather than part of its value, like in `const X: Foo = Foo { x: 1 }`, or a use tree
+fn is_braced_body(item: &[TokenTree]) -> bool {
+    // An `=` inside of generics, like `Iterator<Item = T>`, doesn't start a value
+    let mut depth = 0;
+    for tt in item {
+        match *tt {
+            TtToken(_, Token::Lt) => { depth += 1 },
+            TtToken(_, Token::Gt) => { depth -= cmp::min(depth, 1) },
+            TtToken(_, Token::BinOp(BinOpToken::Shr)) => { depth -= cmp::min(depth, 2) },
+            TtToken(_, Token::Eq) if depth == 0 => { return false },
+            TtToken(_, ref tok) if tok.is_keyword(keywords::Use) => { return false },
+            _ => { () } } };
+    true }
+// Blocks which make up the body of an item, rather than being part of an expression
+fn is_item_block(block_flag: BlockFlag) -> bool {
+    match block_flag {
+        BlockFlag::Module | BlockFlag::Impl | BlockFlag::Trait |
+            BlockFlag::Extern | BlockFlag::EnumStruct | BlockFlag::Fn | BlockFlag::Use => { true },
+        _ => { false } } }
+// Blocks which are part of an expression, and so can be the value of an item
+fn is_value_block(block_flag: BlockFlag) -> bool {
+    match block_flag {
+        BlockFlag::None | BlockFlag::Match | BlockFlag::StructLit => { true },
+        _ => { false } } }
+// Blocks which are opened by a pair of delimiters, and are never closed by dedenting
+fn is_delimited_block(block_flag: BlockFlag) -> bool {
+    match block_flag {
+        BlockFlag::Delimited | BlockFlag::List | BlockFlag::Verbatim => { true },
+        _ => { false } } }
+// Close the innermost block. Once a use tree is closed in anything other than
+// another use tree, it is a whole `use` item, which needs a semicolon.
+fn close_block(file: &mut File, indent_stack: &mut Vec<(usize, BlockFlag)>) {
+    let (_, block_flag) = indent_stack.pop().unwrap();
+    write!(file, " }}").unwrap();
+    if block_flag == BlockFlag::Use && indent_stack.last().unwrap().1 != BlockFlag::Use {
+        write!(file, ";").unwrap() } }
+// Whether the last line already ended with a separator, or there is no last line
+fn has_separator(prev_tt: Option<&TokenTree>) -> bool {
+    match prev_tt {
+        None | Some(&TtToken(_, Token::Comma)) | Some(&TtToken(_, Token::Semi)) => { true },
+        _ => { false } } }
+// The separator which goes between the lines of a block
+fn line_separator(block_flag: BlockFlag) -> &'static str {
+    match block_flag {
+        BlockFlag::None | BlockFlag::Fn | BlockFlag::Module | BlockFlag::Impl | BlockFlag::Trait |
+            BlockFlag::Extern | BlockFlag::MacroRules | BlockFlag::Macro => { ";" },
+        BlockFlag::Delimited | BlockFlag::Verbatim => { "" },
+        _ => { "," } } }
+// Whether a token tree can be the end of a struct literal's path, like `Foo` or `Self`.
+// Types are named in upper camel case, so a lowercase name, like a variable being
+// matched on, isn't one.
+fn is_path_end(tt: Option<&TokenTree>) -> bool {
+    match tt {
+        Some(&TtToken(_, Token::Ident(ref id, IdentStyle::Plain))) => {
+            id.as_str().chars().next().map_or(false, |c| c.is_uppercase()) },
+        _ => { false } } }
+// Whether a token tree can come before the parentheses of a call, like `foo` in
+// `foo(x)`, rather than a tuple
+fn is_call(prev_tt: Option<&TokenTree>) -> bool {
+    match prev_tt {
+        Some(&TtToken(_, Token::Not)) | Some(&TtDelimited(..)) => { true },
+        Some(&TtToken(_, ref tok)) => {
+            tok.is_plain_ident() && (!tok.is_any_keyword() || tok.is_keyword(keywords::SelfType)) },
+        _ => { false } } }
+// The number of elements in a list whose elements start at column `col`. Each line
+// at that column starts one, and a separator on the same line starts another.
+fn list_len(psess: &parse::ParseSess, config: Config, tts: &[TokenTree], col: usize) -> usize {
+    let mut len = 0;
+    let mut last_line = usize::MAX;
+    for (i, tt) in tts.iter().enumerate() {
+        let (line, first_col, last, _) = ends_from_span(psess, config, tt.get_span());
+        let shares_line = tts.get(i + 1).map_or(false, |next: &TokenTree| {
+            ends_from_span(psess, config, next.get_span()).0 == last }
+        );
+        match *tt {
+            TtToken(_, Token::Comma) | TtToken(_, Token::Semi)
+                if shares_line && pos_indent(psess, config, tt.get_span().lo) == col => { len += 1 },
+            _ if line != last_line && first_col == col => { len += 1 },
+            _ => { () } };
+        last_line = last };
+    len }
+// Whether a token tree is the `!` of a macro invocation, like `lazy_static!`
+fn is_macro_bang(tt: Option<&TokenTree>) -> bool {
+    match tt {
+        Some(&TtToken(_, Token::Not)) => { true },
+        _ => { false } } }
+// The arguments of a `[slag(...)]` attribute, which is read by slag rather than rustc
+fn slag_attr_args(tt: &TokenTree) -> Option<&[TokenTree]> {
+    if let TtDelimited(_, ref attr) = *tt {
+        if attr.delim == DelimToken::Bracket && attr.tts.len() == 2 {
+            match (&attr.tts[0], &attr.tts[1]) {
+                (&TtToken(_, Token::Ident(ref id, IdentStyle::Plain)), &TtDelimited(_, ref args))
+                        if id.as_str() == "slag" && args.delim == DelimToken::Paren => {
+                    return Some(&args.tts[..]) },
+                _ => { () } } } };
+    None }
+// Whether the arguments of a slag attribute are just `verbatim`
+fn is_verbatim_marker(args: &[TokenTree]) -> bool {
+    args.len() == 1 && match args[0] {
+        TtToken(_, Token::Ident(ref id, IdentStyle::Plain)) => { id.as_str() == "verbatim" },
+        _ => { false } } }
+// `dedent "..."` is a string literal with the indentation its lines have in common
+// removed, so it can be indented along with the code around it. A first line with
+// nothing after the opening quote is dropped, and so is the indentation before the
+// closing quote. Only whitespace is removed, so escapes are left as they were. Raw
+// strings stay raw, and any other literal can't be dedented.
+fn dedent_str_lit(config: Config, tok: &Token) -> Option<String> {
+    let (name, hashes) = match *tok {
+        Token::Literal(Lit::Str_(name), None) => { (name, None) },
+        Token::Literal(Lit::StrRaw(name, n), None) => {
+            (name, Some(iter::repeat("#").take(n).collect::<String>())) },
+        _ => { return None } };
+    let mut lines = name.as_str().split('\n');
+    let first = lines.next().unwrap();
+    let rest: Vec<&str> = lines.collect();
+    let indent = rest.iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| text_width(config, indent_str(line)))
+        .min().unwrap_or(0);
+    let mut dedented = Vec::new();
+    if rest.is_empty() || !first.trim().is_empty() {
+        dedented.push(first.to_string()) };
+    for line in rest {
+        if line.trim().is_empty() {
+            dedented.push(String::new()) }
+        else {
+            dedented.push(strip_indent(config, line, indent)) } };
+    match hashes {
+        Some(hashes) => { Some(format!("r{}\"{}\"{}", hashes, dedented.join("\n"), hashes)) },
+        None => { Some(format!("\"{}\"", dedented.join("\n"))) } } }
+// A line with `width` columns of its indentation removed. A tab which only partly
+// fits in them is replaced by spaces for the rest of its width.
+fn strip_indent(config: Config, line: &str, width: usize) -> String {
+    let mut stripped = 0;
+    for (i, c) in line.char_indices() {
+        if stripped >= width || !c.is_whitespace() {
+            let spaces: String = iter::repeat(" ").take(stripped - width).collect();
+            return spaces + &line[i..] };
+        stripped = text_width(config, &line[..i + c.len_utf8()]) };
+    String::new() }
+fn is_literal(tt: &TokenTree) -> bool {
+    match *tt {
+        TtToken(_, Token::Literal(..)) => { true },
+        _ => { false } } }
+// Whether a token is the opener, ignoring where it is
+fn is_opener_token(opener: Opener, tok: &Token) -> bool {
+    match opener {
+        Opener::FatArrow => { *tok == Token::FatArrow },
+        Opener::Colon => { *tok == Token::Colon },
+        Opener::Do => { tok.is_keyword(keywords::Do) } } }
+// Whether a token opens a block inside of a block with the given flag
+fn opens_block(psess: &parse::ParseSess,
+               config: Config,
+               block_flag: BlockFlag,
+               tok: &Token,
+               span: Span,
+               next: Option<&&TokenTree>,
+               colon_state: ColonState) -> bool {
+    match block_flag {
+        BlockFlag::Match | BlockFlag::MacroRules => { *tok == Token::FatArrow },
+        BlockFlag::Verbatim => { false },
+        _ if config.opener == Opener::Colon && *tok == Token::Colon => {
+            // A `:` is only an opener when it is the last thing on its line, or is
+            // followed by the `...` placeholder, as it is used all over the place.
+            match next {
+                _ if colon_state == ColonState::LetType || colon_state == ColonState::WhereBounds => {
+                    false },
+                None | Some(&&TtToken(_, Token::DotDotDot)) => { true },
+                Some(tt) => { ends_from_span(psess, config, tt.get_span()).0 > ends_from_span(psess, config, span).2 } } },
+        _ => { is_opener_token(config.opener, tok) } } }
+fn is_comma(tt: &TokenTree) -> bool {
+    match *tt {
+        TtToken(_, Token::Comma) => { true },
+        _ => { false } } }
+fn lit_str(lit: Lit) -> Option<String> {
+    match lit {
+        Lit::Str_(name) => { Some(name.as_str().to_string()) },
+        _ => { None } } }
+fn lit_usize(lit: Lit) -> Option<usize> {
+    match lit {
+        Lit::Integer(name) => { name.as_str().parse().ok() },
+        _ => { None } } }
+// Read the `#![slag(...)]` pragmas from the inner attributes at the top of a file
+// into the config. The pragmas are only meant for slag, so they are removed.
+fn read_pragmas(psess: &parse::ParseSess,
+                config: &mut Config,
+                tts: &[TokenTree]) -> Vec<TokenTree> {
+    let mut kept = Vec::new();
+    let mut i = 0;
+    while i + 2 < tts.len() {
+        match (&tts[i], &tts[i + 1]) {
+            (&TtToken(_, Token::Pound), &TtToken(_, Token::Not)) => { () },
+            _ => { break } };
+        if let Some(args) = slag_attr_args(&tts[i + 2]) {
+            read_pragma_args(psess, config, args) }
+        else {
+            kept.extend(tts[i..i + 3].iter().cloned()) };
+        i += 3 };
+    kept.extend(tts[i..].iter().cloned());
+    kept }
+fn read_pragma_args(psess: &parse::ParseSess, config: &mut Config, args: &[TokenTree]) {
+    for pragma in args.split(is_comma) {
+        let pragma_tts = (pragma.get(0), pragma.get(1), pragma.get(2));
+        let (key_span, key, value_span, value) = match pragma_tts {
+            (Some(&TtToken(key_span, Token::Ident(key, IdentStyle::Plain))),
+                    Some(&TtToken(_, Token::Eq)),
+                    Some(&TtToken(value_span, Token::Literal(value, None)))) if pragma.len() == 3 => {
+                (key_span, key, value_span, value) },
+            // Allow a trailing comma
+            (None, _, _) => { continue },
+            (Some(first), _, _) => {
+                let span = mk_sp(first.get_span().lo, pragma.last().unwrap().get_span().hi);
+                psess.span_diagnostic.span_err(span, "expected a pragma like `key = value`");
+                continue } };
+        let invalid = format!("invalid value for the `{}` pragma", key.as_str());
+        match key.as_str() {
+            "opener" => {
+                match lit_str(value).and_then(|s| opener_from_str(&s)) {
+                    Some(opener) => { config.opener = opener },
+                    None => { psess.span_diagnostic.span_err(value_span, &invalid) } } },
+            "indent" => {
+                match lit_usize(value) {
+                    Some(indent) if indent > 0 => { config.indent = Some(indent) },
+                    _ => { psess.span_diagnostic.span_err(value_span, &invalid) } } },
+            "tabs" => {
+                match lit_str(value).and_then(|s| tabs_from_str(&s)) {
+                    Some(tabs) => { config.tabs = tabs },
+                    None => { psess.span_diagnostic.span_err(value_span, &invalid) } } },
+            "tab_width" => {
+                match lit_usize(value) {
+                    Some(tab_width) if tab_width > 0 => { config.tab_width = tab_width },
+                    _ => { psess.span_diagnostic.span_err(value_span, &invalid) } } },
+            "mixed" => {
+                match lit_str(value).and_then(|s| mixed_from_str(&s)) {
+                    Some(mixed) => { config.mixed = mixed },
+                    None => { psess.span_diagnostic.span_err(value_span, &invalid) } } },
+            _ => {
+                let msg = format!("unknown slag pragma `{}`", key.as_str());
+                psess.span_diagnostic.span_err(key_span, &msg) } } } }
+// Replace the braces and separators which only repeat what the layout says, like
+// the ones left over from writing Rust, with the layout. Each one is warned about,
+// and the edit which removes it from the source is added to `fixes`. Verbatim
+// regions and macro definitions are plain Rust, so they are left alone, and so is
+// everything inside of braces which are kept.
+fn migrate_tts(psess: &parse::ParseSess,
+               config: Config,
+               tts: &[TokenTree],
+               commas: bool,
+               fixes: &mut Vec<(Span, String)>) -> Vec<TokenTree> {
+    let mut migrated = Vec::new();
+    let mut i = 0;
+    while i < tts.len() {
+        // The number of token trees to copy through untouched
+        let kept = match tts[i] {
+            TtToken(_, Token::Pound) if tts.get(i + 1).and_then(|tt| slag_attr_args(tt))
+                    .map_or(false, is_verbatim_marker) => {
+                match tts.get(i + 2) {
+                    Some(first) => {
+                        let (first_line, _, _, _) = ends_from_span(psess, config, first.get_span());
+                        let first_indent = pos_indent(psess, config, first.get_span().lo);
+                        2 + verbatim_len(psess, config, &tts[i + 2..], first_line, first_indent) },
+                    None => { 2 } } },
+            TtToken(span, Token::Ident(ref id, IdentStyle::Plain)) if id.as_str() == "macro_rules" => {
+                let (line, _, _, _) = ends_from_span(psess, config, span);
+                verbatim_len(psess, config, &tts[i..], line, pos_indent(psess, config, span.lo)) },
+            TtToken(span, Token::Semi) if is_redundant_semi(psess, config, span, tts.get(i + 1)) => {
+                psess.span_diagnostic.span_warn(span, "redundant `;`, the line after it is on its own line");
+                fixes.push((span, String::new()));
+                i += 1;
+                continue },
+            TtToken(span, Token::Comma) if commas && is_line_end(psess, span.hi) => {
+                let msg = "redundant `,`, the lines of the block are separated by the layout";
+                psess.span_diagnostic.span_warn(span, msg);
+                fixes.push((span, String::new()));
+                i += 1;
+                continue },
+            TtDelimited(_, ref delimited)
+                    if is_redundant_brace(psess, config, &tts[..i], delimited, tts.get(i + 1), commas) => {
+                let (open, close) = (delimited.open_span, delimited.close_span);
+                let msg = "redundant braces, the block is laid out by its indentation";
+                psess.span_diagnostic.span_warn(open, msg);
+                // The `=>` of a match arm already opens its body
+                let head_end = tts[i - 1].get_span().hi;
+                if let TtToken(_, Token::FatArrow) = tts[i - 1] {
+                    fixes.push((mk_sp(head_end, open.hi), String::new())) }
+                else {
+                    let opener = match config.opener {
+                        Opener::Colon => { ":".to_string() },
+                        _ => { format!(" {}", opener_to_str(config.opener)) } };
+                    fixes.push((mk_sp(head_end, open.hi), opener));
+                    migrated.push(TtToken(open, opener_to_token(config.opener))) };
+                let has_commas = is_comma_head(psess, config, &tts[..i], open);
+                migrated.extend(migrate_tts(psess, config, &delimited.tts, has_commas, fixes));
+                // The `}` goes, along with the line it is on unless a comment follows it.
+                // An `else` after it is moved to where the `}` was, so that it lines up
+                // with the head of the block.
+                match tts.get(i + 1) {
+                    Some(&TtToken(else_span, ref tok)) if !is_line_end(psess, close.hi) &&
+                            tok.is_keyword(keywords::Else) => {
+                        fixes.push((mk_sp(close.lo, else_span.lo), String::new()));
+                        migrated.push(TtToken(mk_sp(close.lo, else_span.hi), tok.clone()));
+                        i += 2 },
+                    Some(&TtToken(comma, Token::Comma)) if !is_line_end(psess, close.hi) => {
+                        fixes.push((closer_span(psess, close, comma.hi), String::new()));
+                        i += 2 },
+                    _ => {
+                        fixes.push((closer_span(psess, close, close.hi), String::new()));
+                        i += 1 } };
+                continue },
+            TtDelimited(span, ref delimited) if delimited.delim != DelimToken::Brace => {
+                migrated.push(TtDelimited(span, Rc::new(Delimited {
+                    delim: delimited.delim,
+                    open_span: delimited.open_span,
+                    tts: migrate_tts(psess, config, &delimited.tts, false, fixes),
+                    close_span: delimited.close_span }
+                )));
+                i += 1;
+                continue },
+            _ => { 1 } };
+        migrated.extend(tts[i..i + kept].iter().cloned());
+        i += kept };
+    migrated }
+// A `;` is redundant at the end of a line which is followed by another line of the
+// same block, as the layout already separates them. On the last line of a block it
+// throws away the value of the block, so it is kept there.
+fn is_redundant_semi(psess: &parse::ParseSess,
+                     config: Config,
+                     span: Span,
+                     next: Option<&TokenTree>) -> bool {
+    match next {
+        Some(next) => {
+            let next_lo = next.get_span().lo;
+            is_line_end(psess, span.hi) && is_line_start(psess, next_lo) &&
+                pos_indent(psess, config, span.lo) == pos_indent(psess, config, next_lo) },
+        None => { false } } }
+// Braces are redundant when they are laid out like a block would be: the `{` ends
+// the head of the block, the body is indented past it, and the `}` is on its own
+// line, or followed by an `else`, or by the `,` after a match arm or field. Use trees are
+// laid out differently in braces, so they are left alone.
+fn is_redundant_brace(psess: &parse::ParseSess,
+                      config: Config,
+                      head: &[TokenTree],
+                      delimited: &Delimited,
+                      next: Option<&TokenTree>,
+                      commas: bool) -> bool {
+    let open = delimited.open_span;
+    let close = delimited.close_span;
+    let head_indent = pos_indent(psess, config, open.lo);
+    let is_use_tree = match head.last() {
+        Some(&TtToken(_, Token::ModSep)) => { true },
+        _ => { false } };
+    let close_ends_line = match next {
+        Some(&TtToken(_, ref tok)) if tok.is_keyword(keywords::Else) => { true },
+        Some(&TtToken(span, Token::Comma)) if commas => { is_line_end(psess, span.hi) },
+        _ => { is_line_end(psess, close.hi) } };
+    delimited.delim == DelimToken::Brace && !head.is_empty() && !delimited.tts.is_empty() &&
+        !is_use_tree && !is_line_start(psess, open.lo) && is_line_end(psess, open.hi) &&
+        is_line_start(psess, close.lo) && close_ends_line &&
+        pos_indent(psess, config, close.lo) == head_indent &&
+        pos_indent(psess, config, delimited.tts[0].get_span().lo) > head_indent }
+// Whether the block a `{` opens separates its lines with commas, like the arms of a
+// match, the fields and variants of a struct or enum, or the fields of a struct literal
+fn is_comma_head(psess: &parse::ParseSess, config: Config, head: &[TokenTree], open: Span) -> bool {
+    // The head goes back to the line its item or statement starts on
+    let head_indent = pos_indent(psess, config, open.lo);
+    let mut words = Vec::new();
+    for tt in head.iter().rev() {
+        match *tt {
+            TtToken(_, Token::Semi) => { break },
+            TtDelimited(_, ref delimited) if delimited.delim == DelimToken::Brace => { break },
+            TtToken(_, Token::Ident(ref id, IdentStyle::Plain)) => { words.push(id.as_str().to_string()) },
+            _ => { () } };
+        let lo = tt.get_span().lo;
+        if is_line_start(psess, lo) && pos_indent(psess, config, lo) <= head_indent {
+            break } };
+    let has_word = |names: &[&str]| words.iter().any(|word| names.contains(&&word[..]));
+    has_word(&["match", "struct", "enum", "union"]) ||
+        (is_path_end(head.last()) &&
+         !has_word(&["fn", "impl", "trait", "mod", "extern", "if", "while", "for", "loop", "else"])) }
+// Apply the fixes found by `migrate_tts` to the source file
+fn apply_fixes(psess: &parse::ParseSess, source: &str, mut fixes: Vec<(Span, String)>) {
+    if fixes.is_empty() {
+        return };
+    let filemap = psess.codemap().lookup_char_pos(fixes[0].0.lo).file;
+    let mut src = filemap.src.as_ref().unwrap().to_string();
+    // Going from the end of the file back keeps the offsets of the earlier fixes right
+    fixes.sort_by(|a, b| b.0.lo.0.cmp(&a.0.lo.0));
+    for (span, fix) in fixes {
+        let lo = (span.lo.0 - filemap.start_pos.0) as usize;
+        let hi = (span.hi.0 - filemap.start_pos.0) as usize;
+        let rest = src.split_off(hi);
+        src.truncate(lo);
+        src.push_str(&fix);
+        src.push_str(&rest) };
+    File::create(Path::new(source)).unwrap().write_all(src.as_bytes()).unwrap() }
+// Check the whitespace the line a token tree starts on is indented with. Lines in
+// the same block, or in blocks nested in each other, start their indentation the
+// same way, so it has to agree with the line `prev` starts on.
+fn check_indent_chars(psess: &parse::ParseSess, config: Config, span: Span, prev: Option<Span>) {
+    let loc = psess.codemap().lookup_char_pos(span.lo);
+    let indent = indent_str(loc.file.get_line(loc.line - 1).unwrap());
+    let start = loc.file.lines.borrow()[loc.line - 1];
+    let indent_span = mk_sp(start, start + BytePos(indent.len() as u32));
+    if config.tabs == Tabs::Error && indent.contains('\t') {
+        psess.span_diagnostic.span_err(indent_span,
+                                       "tabs can't be used for indentation in this file") }
+    else if let Some(prev) = prev {
+        let prev_loc = psess.codemap().lookup_char_pos(prev.lo);
+        let prev_indent = indent_str(prev_loc.file.get_line(prev_loc.line - 1).unwrap());
+        if !indent.starts_with(prev_indent) && !prev_indent.starts_with(indent) {
+            let msg = "indentation mixes tabs and spaces with the line before it";
+            match config.mixed {
+                Mixed::Reject => { psess.span_diagnostic.span_err(indent_span, msg) },
+                Mixed::Warn => { psess.span_diagnostic.span_warn(indent_span, msg) },
+                Mixed::Normalize => { () } } } } }
+// Whether a line which is indented past the line before it carries on from that
+// line, because one of them ends or starts part way through an expression or type
+fn continues_line(prev_tt: Option<&TokenTree>, tt: &TokenTree, next_block_flag: BlockFlag) -> bool {
+    let ends_open = match prev_tt {
+        Some(&TtToken(_, ref tok)) => {
+            match *tok {
+                Token::Eq | Token::Lt | Token::Le | Token::EqEq | Token::Ne | Token::Ge |
+                    Token::AndAnd | Token::OrOr | Token::BinOp(_) | Token::BinOpEq(_) |
+                    Token::Comma | Token::Colon | Token::ModSep | Token::RArrow | Token::Dot => { true },
+                // The bounds of a `where` clause can start on the line after it, like the
+                // iterator of a `for` loop and the type of a cast
+                _ => { tok.is_keyword(keywords::Where) || tok.is_keyword(keywords::In) ||
+                        tok.is_keyword(keywords::As) } } },
+        _ => { false } };
+    let starts_open = match *tt {
+        // `impl Trait for Type` can be split before the `for`
+        TtToken(_, Token::Ident(ref id, IdentStyle::Plain)) => {
+            match id.as_str() {
+                "where" | "as" | "in" => { true },
+                "for" => { next_block_flag == BlockFlag::Impl },
+                _ => { false } } },
+        TtToken(_, ref tok) => {
+            match *tok {
+                Token::Eq | Token::Lt | Token::Le | Token::EqEq | Token::Ne | Token::Ge | Token::Gt |
+                    Token::AndAnd | Token::OrOr | Token::BinOp(_) | Token::BinOpEq(_) |
+                    Token::ModSep | Token::RArrow | Token::Dot | Token::Question => { true },
+                _ => { false } } },
+        _ => { false } };
+    ends_open || starts_open }
+fn is_where(tt: &TokenTree) -> bool {
+    match *tt {
+        TtToken(_, ref tok) => { tok.is_keyword(keywords::Where) },
+        _ => { false } } }
+// Whether the token trees after a `;` on the given line start another match arm, like
+// `None => 0`, rather than another statement in the same arm
+fn starts_arm(psess: &parse::ParseSess, config: Config, tts: &[TokenTree], line: usize) -> bool {
+    for (i, tt) in tts.iter().enumerate() {
+        let (first_line, _, _, _) = ends_from_span(psess, config, tt.get_span());
+        if first_line != line {
+            return false };
+        match *tt {
+            TtToken(_, Token::FatArrow) => { return true },
+            TtToken(_, Token::Semi) => { return false },
+            // Patterns can bind with `ref` and `mut`, and be followed by a guard
+            TtToken(_, ref tok) if tok.is_any_keyword() &&
+                    !tok.is_keyword(keywords::Ref) && !tok.is_keyword(keywords::Mut) &&
+                    !(i > 0 && tok.is_keyword(keywords::If)) => {
+                return false },
+            _ => { () } } };
+    false }
+// Whether the head of an item or control flow expression with the given flag
+// is followed by a block
+fn has_block_body(next_block_flag: BlockFlag) -> bool {
+    match next_block_flag {
+        BlockFlag::Fn | BlockFlag::Impl | BlockFlag::Trait | BlockFlag::EnumStruct |
+            BlockFlag::Match | BlockFlag::Module => { true },
+        _ => { false } } }
+// The indentation a line which dedents should have. If it isn't indented to the
+// same column as one of the blocks it could close, that is reported, and it is
+// treated as if it was indented to the nearest one, so that the rest of the file
+// can be checked too. Inside of a pair of delimiters, a line which closes every
+// block opened in them can be indented anywhere past the line they were opened on,
+// and so can the line they are closed on.
+fn dedent_target(psess: &parse::ParseSess,
+                 config: Config,
+                 indent_stack: &[(usize, BlockFlag)],
+                 new_indent: usize,
+                 span: Span,
+                 closing_line: bool) -> usize {
+    let mut levels = Vec::new();
+    for &(indent, block_flag) in indent_stack.iter().rev() {
+        if indent == new_indent {
+            return new_indent };
+        if is_delimited_block(block_flag) {
+            if closing_line || (new_indent > indent && levels.is_empty()) {
+                return new_indent };
+            levels.push(indent);
+            break };
+        // Empty blocks can't be matched
+        if indent != usize::MAX {
+            levels.push(indent) } };
+    levels.sort();
+    levels.dedup();
+    // Ties go to the outer block, as the levels are sorted
+    let nearest = *levels.iter().min_by_key(|&&level| {
+        if level > new_indent {
+            level - new_indent }
+        else {
+            new_indent - level } }
+    ).unwrap();
+    let loc = psess.codemap().lookup_char_pos(span.lo);
+    let start = loc.file.lines.borrow()[loc.line - 1];
+    let indent_span = mk_sp(start, span.lo);
+    let levels: Vec<String> = levels.iter().map(|level| level.to_string()).collect();
+    let msg = format!("this line is indented by {} columns, which doesn't match any of the \
+                       blocks around it. They are indented by {} columns.",
+                      new_indent, levels.join(", "));
+    psess.span_diagnostic.span_err(indent_span, &msg);
+    // The suggestion uses the same whitespace as the nearest block, which is indented
+    // like the last line above this one with its indentation
+    let indent = (0..loc.line - 1).rev()
+        .filter_map(|line| loc.file.get_line(line))
+        .find(|line| !line.trim().is_empty() && line_indent(config, line) == nearest)
+        .map_or(iter::repeat(" ").take(nearest).collect(), |line| indent_str(&line).to_string());
+    psess.span_diagnostic.span_suggestion(
+        indent_span,
+        &format!("indent it by {} columns to match the nearest block", nearest),
+        indent
+    );
+    nearest }
+// The number of token trees which start on the given line, or on the lines after it
+// which are indented past it. This is how far a verbatim region goes.
+fn verbatim_len(psess: &parse::ParseSess,
+                config: Config,
+                tts: &[TokenTree],
+                line: usize,
+                indent: usize) -> usize {
+    tts.iter().take_while(|tt| {
+        let (tt_line, col, _, _) = ends_from_span(psess, config, tt.get_span());
+        tt_line == line || col > indent }
+    ).count() }
+fn ends_from_span(psess: &parse::ParseSess,
+                  config: Config,
+                  span: Span) -> (usize, usize, usize, usize) {
     let flines = psess.codemap().span_to_lines(span).unwrap();
     let first_line = flines.lines.first().unwrap();
     let last_line = flines.lines.last().unwrap();
-    (first_line.line_index, first_line.start_col.0,
-     last_line.line_index, last_line.end_col.0) }
+    // The columns are in chars, which don't line up with what is displayed when
+    // there are tabs or wide chars, so measure the text before them instead
+    let first_src = flines.file.get_line(first_line.line_index).unwrap();
+    let last_src = flines.file.get_line(last_line.line_index).unwrap();
+    (first_line.line_index, col_width(config, first_src, first_line.start_col),
+     last_line.line_index, col_width(config, last_src, last_line.end_col)) }
+// The width of some text as it is displayed, where tabs go to the next tab stop
+fn text_width(config: Config, text: &str) -> usize {
+    text.chars().fold(0, |width, c| {
+        if c == '\t' {
+            (width / config.tab_width + 1) * config.tab_width }
+        else {
+            width + char_width(c) } }
+    ) }
+// The width of a line up to a column in chars
+fn col_width(config: Config, src_line: &str, col: CharPos) -> usize {
+    let end = src_line.char_indices().nth(col.0).map_or(src_line.len(), |(i, _)| i);
+    text_width(config, &src_line[..end]) }
+// The whitespace at the start of a line
+fn indent_str(src_line: &str) -> &str {
+    &src_line[..src_line.len() - src_line.trim_left().len()] }
+fn line_indent(config: Config, src_line: &str) -> usize {
+    text_width(config, indent_str(src_line)) }
+// The indentation of the line `pos` is on
+fn pos_indent(psess: &parse::ParseSess, config: Config, pos: BytePos) -> usize {
+    line_indent(config, &split_line(psess, pos).0) }
+// The text of the line `pos` is on, split into what comes before and after it
+fn split_line(psess: &parse::ParseSess, pos: BytePos) -> (String, String) {
+    let loc = psess.codemap().lookup_char_pos(pos);
+    let line = loc.file.get_line(loc.line - 1).unwrap();
+    let split = line.char_indices().nth(loc.col.0).map_or(line.len(), |(i, _)| i);
+    (line[..split].to_string(), line[split..].to_string()) }
+// Whether only whitespace comes before `pos` on its line
+fn is_line_start(psess: &parse::ParseSess, pos: BytePos) -> bool {
+    split_line(psess, pos).0.trim().is_empty() }
+// Whether only whitespace or a comment comes after `pos` on its line
+fn is_line_end(psess: &parse::ParseSess, pos: BytePos) -> bool {
+    let rest = split_line(psess, pos).1;
+    let rest = rest.trim_left();
+    rest.is_empty() || rest.starts_with("//") }
+// What to remove for a `}` which isn't needed anymore, up to `end`. That is the whole
+// line it is on, unless a comment comes after it, which then takes its place.
+fn closer_span(psess: &parse::ParseSess, close: Span, end: BytePos) -> Span {
+    let rest = split_line(psess, end).1;
+    let comment = rest.trim_left();
+    if comment.is_empty() {
+        line_span(psess, close.lo) }
+    else {
+        mk_sp(close.lo, end + BytePos((rest.len() - comment.len()) as u32)) } }
+// The whole of the line `pos` is on, including the newline at the end of it
+fn line_span(psess: &parse::ParseSess, pos: BytePos) -> Span {
+    let loc = psess.codemap().lookup_char_pos(pos);
+    let lines = loc.file.lines.borrow();
+    mk_sp(lines[loc.line - 1], lines.get(loc.line).cloned().unwrap_or(loc.file.end_pos)) }
+fn print_comments(psess: &parse::ParseSess,
+                  config: Config,
+                  last_pos: &mut (usize, usize),
+                  file: &mut File,
+                  comments: &mut Comments,
+                  before: BytePos) {
+    // Comments are only written out right before the token which follows them,
+    // so any separators or block closers for the previous line end up before
+    // a trailing comment, rather than being commented out by it.
+    while comments.peek().map_or(false, |c| c.pos < before) {
+        let comment = comments.next().unwrap();
+        let loc = psess.codemap().lookup_char_pos(comment.pos);
+        let first_line = loc.line - 1;
+        let first_col = col_width(config, loc.file.get_line(first_line).unwrap(), loc.col);
+        // Blank lines are recorded as comments with no lines, and are skipped here
+        for (i, line) in comment.lines.iter().enumerate() {
+            // Consecutive line comments are grouped together even if they are at
+            // different indentations, so look up each line's own column
+            let col = if i > 0 && line.starts_with("//") {
+                line_indent(config, loc.file.get_line(first_line + i).unwrap()) }
+            else {
+                first_col };
+            if first_line + i > last_pos.0 {
+                write!(file, "\n").unwrap();
+                if !line.is_empty() {
+                    for _ in 0..col {
+                        write!(file, " ").unwrap() } } }
+            else {
+                for _ in last_pos.1..col {
+                    write!(file, " ").unwrap() } };
+            write!(file, "{}", line).unwrap();
+            *last_pos = (first_line + i, col + text_width(config, line)) } } }
 fn print_with_span(psess: &parse::ParseSess,
+                   config: Config,
                    last_pos: &mut (usize, usize),
                    file: &mut File,
+                   comments: &mut Comments,
                    tok: &str,
                    span: Span) {
-    let (first_line, first_col, last_line, last_col) = ends_from_span(psess, span);
+    print_comments(psess, config, last_pos, file, comments, span.lo);
+    let (first_line, first_col, last_line, last_col) = ends_from_span(psess, config, span);
     if first_line > last_pos.0 {
         write!(file, "\n").unwrap();
         for _ in 0..first_col {
@@ -62,90 +872,418 @@ fn print_with_span(psess: &parse::ParseSess,
 fn handle_tts(psess: &parse::ParseSess,
               last_pos: &mut (usize, usize),
               file: &mut File,
-              tts: &[TokenTree]) {
+              comments: &mut Comments,
+              config: Config,
+              tts: &[TokenTree],
+              base_block: (usize, BlockFlag)) {
     let mut last_line = last_pos.0;
     let mut iter = tts.iter().peekable();
-    let mut indent_stack: Vec<(usize, BlockFlag)> = vec![(0, BlockFlag::Module)];
+    let mut indent_stack: Vec<(usize, BlockFlag)> = vec![base_block];
     let mut next_block_flag = BlockFlag::None;
-    let mut skip_next_semi = false;
+    let mut saw_block_keyword = false;
+    let mut colon_state = ColonState::Opener;
+    let mut item_indent = 0;
+    let mut verbatim_next = false;
+    let mut attr_state = AttrState::None;
+    let mut prev_tt = None;
+    // Where the item or statement the current line is part of starts in `tts`, and
+    // where the ones each open block is part of start
+    let mut item_start = 0;
+    let mut item_starts = Vec::new();
+    // Inside of delimiters, the line they are closed on, if anything comes before them on it
+    let end_line = match tts.last() {
+        Some(tt) if split_line(psess, tt.get_span().hi).1.trim_left()
+                .starts_with(&[')', ']', '}'][..]) => {
+            ends_from_span(psess, config, tt.get_span()).2 },
+        _ => { usize::MAX } };
     loop {
+        // get the next token in the iterator sequence, and where it is in `tts`
+        let index = tts.len() - iter.clone().count();
         let opt_tt = iter.next();
+        // The last token tree of a verbatim region, which stands in for the marker
+        let mut region_end = None;
+        // Check if we should insert a semicolon or close a block!
         if let Some(tt) = opt_tt {
-            let (new_line, new_indent, new_last_line, _) = ends_from_span(psess, tt.get_span());
+            let (new_line, new_indent, new_last_line, _) = ends_from_span(psess, config, tt.get_span());
+            // Token trees like parentheses can start part way through a line and end on another
+            let starts_line = last_line == usize::MAX || new_line > last_line;
+            if starts_line {
+                let prev_span = prev_tt.map(|tt: &TokenTree| tt.get_span());
+                check_indent_chars(psess, config, tt.get_span(), prev_span) };
             if last_line == usize::MAX {
                 last_line = new_line }
             else if new_last_line > last_line {
                 last_line = new_last_line;
                 let (old_indent, block_flag) = *indent_stack.last().unwrap();
-                if new_indent == old_indent {
-                    match block_flag {
-                        BlockFlag::None | BlockFlag::Module => {
-                            if !skip_next_semi {
-                                write!(file, ";").unwrap() }
-                            else {
-                                skip_next_semi = false } },
-                        _ => {
-                            write!(file, ",").unwrap() } };
-                    next_block_flag = BlockFlag::None }
+                let new_indent = if new_indent < old_indent {
+                    dedent_target(psess, config, &indent_stack, new_indent, tt.get_span(),
+                                  new_line == end_line) }
+                else {
+                    new_indent };
+                // The head of an item carries on over the lines indented past its first
+                // line, and over a `where` clause at the same indentation as it
+                let continues_head = is_item_block(next_block_flag) && is_where(tt);
+                if new_indent == old_indent && !continues_head {
+                    // Insert a semicolon or comma!! Attributes and doc comments attach
+                    // to the line after them, so they don't get one.
+                    if attr_state != AttrState::End && !has_separator(prev_tt) {
+                        let item = &tts[item_start..index];
+                        match block_flag {
+                            // Items which end with their body in braces don't get a semicolon
+                            BlockFlag::Module | BlockFlag::Impl | BlockFlag::Trait |
+                                BlockFlag::Extern | BlockFlag::Macro
+                                    if !needs_item_semi(config, block_flag, prev_tt, item, attr_state) => { () },
+                            // A `macro_rules!` written out in braces can't be followed by a semicolon
+                            _ if next_block_flag == BlockFlag::MacroRules &&
+                                    !needs_item_semi(config, block_flag, prev_tt, item, attr_state) => { () },
+                            _ => { write!(file, "{}", line_separator(block_flag)).unwrap() } } };
+                    item_start = index;
+                    // Wipe out the next_block_flag if it is set
+                    next_block_flag = BlockFlag::None;
+                    saw_block_keyword = false;
+                    colon_state = ColonState::Opener }
                 else if new_indent < old_indent {
+                    // Pop items off of the stack until new_indent = old_indent
+                    let mut closed_flag = None;
                     loop {
-                        if let Some(x) = indent_stack.last() {
-                            if x.0 == new_indent {
-                                break } }
+                        let block_flag = if let Some(x) = indent_stack.last() {
+                            // Dedenting can close blocks opened inside of a pair of
+                            // delimiters, but never the delimiters themselves
+                            if x.0 == new_indent || is_delimited_block(x.1) {
+                                break };
+                            x.1 }
                         else {
-                            panic!("Couldn't find indent level") };
-                        write!(file, " }}").unwrap();
-                        indent_stack.pop();
-                        () };
+                            unreachable!("dedent_target always finds a block") };
+                        // Only the innermost block's last line can still need a separator
+                        if closed_flag.is_none() &&
+                                needs_item_semi(config, block_flag, prev_tt, &tts[item_start..index], attr_state) {
+                            write!(file, ";").unwrap() };
+                        close_block(file, &mut indent_stack);
+                        item_start = item_starts.pop().unwrap();
+                        if needs_value_semi(indent_stack.last().unwrap().1, block_flag,
+                                            &tts[item_start..index]) {
+                            write!(file, ";").unwrap() };
+                        closed_flag = Some(block_flag) };
+                    // Wipe out the next_block_flag, as this is a new line too
+                    next_block_flag = BlockFlag::None;
+                    saw_block_keyword = false;
+                    colon_state = ColonState::Opener;
                     let (_, block_flag) = *indent_stack.last().unwrap();
                     match block_flag {
-                        BlockFlag::None => {
+                        // Items declared inside of a function body are statements which
+                        // don't need a semicolon after them
+                        BlockFlag::None | BlockFlag::Fn if closed_flag.map_or(false, is_item_block) => { () },
+                        BlockFlag::None | BlockFlag::Fn => {
                             if let Some(&TtToken(_, Token::Ident(ref id, IdentStyle::Plain))) = opt_tt {
                                 match id.as_str() {
                                     "else" => { () },
                                     _ => { write!(file, ";").unwrap() } } }
                             else {
                                 write!(file, ";").unwrap() } },
-                        BlockFlag::Module => { () },
+                        BlockFlag::MacroRules => { write!(file, ";").unwrap() },
+                        BlockFlag::Module | BlockFlag::Impl | BlockFlag::Trait | BlockFlag::Extern |
+                            BlockFlag::Macro | BlockFlag::Delimited | BlockFlag::Verbatim => { () },
                         _ => {
-                            write!(file, ",").unwrap() } } } } };
+                            write!(file, ",").unwrap() } };
+                    item_start = index }
+                else if starts_line && !is_delimited_block(block_flag) &&
+                        (saw_block_keyword || has_block_body(next_block_flag)) &&
+                        !continues_line(prev_tt, tt, next_block_flag) {
+                    // A line indented past the head of an item or control flow expression
+                    // is its body, and the head is missing its opener. The block is opened
+                    // anyway, so that the rest of the file can be checked too.
+                    if let Some(prev) = prev_tt {
+                        let opener = opener_to_str(config.opener);
+                        let span = prev.get_span();
+                        let msg = format!("missing `{}` before this indented block", opener);
+                        psess.span_diagnostic.span_err(tt.get_span(), &msg);
+                        psess.span_diagnostic.span_suggestion(
+                            mk_sp(span.hi, span.hi),
+                            &format!("add `{}` to the end of the line before it", opener),
+                            format!(" {}", opener)
+                        ) };
+                    write!(file, " {{").unwrap();
+                    indent_stack.push((new_indent, next_block_flag));
+                    item_starts.push(item_start);
+                    item_start = index;
+                    next_block_flag = BlockFlag::None;
+                    saw_block_keyword = false;
+                    colon_state = ColonState::Opener } } };
+        // Keep track of whether this token tree finishes an attribute. Attributes
+        // can be stacked or share a line with their item, and are only left
+        // without a separator when they are the last thing on their line.
+        attr_state = match (attr_state, opt_tt) {
+            (_, Some(&TtToken(_, Token::Pound))) => { AttrState::Start },
+            (AttrState::Start, Some(&TtToken(_, Token::Not))) => { AttrState::Start },
+            (AttrState::Start, Some(&TtDelimited(_, ref delimited)))
+                if delimited.delim == DelimToken::Bracket => { AttrState::End },
+            // Doc comments are written out verbatim, but are sugar for attributes
+            (_, Some(&TtToken(_, Token::DocComment(_)))) => { AttrState::End },
+            _ => { AttrState::None } };
         match opt_tt {
             Some(&TtToken(span, ref tok)) => {
                 match *tok {
-                    Token::Pound => {
-                        skip_next_semi = true;
-                        print_with_span(psess, last_pos, file,
-                                        &pprust::token_to_string(tok), span) },
-                    Token::FatArrow => {
-                        if let (_, BlockFlag::Match) = *indent_stack.last().unwrap() {
-                            print_with_span(psess, last_pos, file, "=>", span) };
+                    // An explicit `;` separates things which share a line, and is written as
+                    // whatever separator the block uses. It also closes a match arm which was
+                    // opened on the same line when another arm follows it, so that several
+                    // arms can share a line.
+                    Token::Semi => {
+                        let loc = psess.codemap().lookup_char_pos(span.lo);
+                        let indent = line_indent(config, loc.file.get_line(loc.line - 1).unwrap());
+                        let len = indent_stack.len();
+                        if len > 1 && indent_stack[len - 2].1 == BlockFlag::Match &&
+                                indent_stack[len - 1].0 > indent &&
+                                starts_arm(psess, config, &tts[index + 1..], loc.line - 1) {
+                            close_block(file, &mut indent_stack);
+                            item_starts.pop();
+                            () };
+                        let sep = match line_separator(indent_stack.last().unwrap().1) {
+                            "" => { ";" },
+                            sep => { sep } };
+                        print_with_span(psess, config, last_pos, file, comments, sep, span);
+                        item_start = index + 1;
+                        next_block_flag = BlockFlag::None;
+                        saw_block_keyword = false;
+                        colon_state = ColonState::Opener },
+                    // `#[slag(verbatim)]` marks the next line, along with the lines indented
+                    // past it, as plain Rust. If the line opens a block, it is only the body
+                    // of the block which is plain Rust, which is used for macros. The marker
+                    // is only meant for slag, so it isn't written out.
+                    Token::Pound if indent_stack.last().unwrap().1 != BlockFlag::Verbatim &&
+                            iter.peek().and_then(|tt| slag_attr_args(tt))
+                                .map_or(false, is_verbatim_marker) => {
+                        iter.next();
+                        attr_state = AttrState::End;
+                        let rest = &tts[tts.len() - iter.clone().count()..];
+                        if let Some(first) = rest.first() {
+                            let (first_line, _, _, _) = ends_from_span(psess, config, first.get_span());
+                            let loc = psess.codemap().lookup_char_pos(first.get_span().lo);
+                            let first_indent = line_indent(config, loc.file.get_line(first_line).unwrap());
+                            let region_len = verbatim_len(psess, config, rest, first_line, first_indent);
+                            let block_flag = indent_stack.last().unwrap().1;
+                            let opens_body = (0..region_len).any(|i| {
+                                let (line, _, _, _) = ends_from_span(psess, config, rest[i].get_span());
+                                match rest[i] {
+                                    TtToken(span, ref tok) if line == first_line => {
+                                        opens_block(psess, config, block_flag, tok, span,
+                                                    rest.get(i + 1).as_ref(), ColonState::Opener) },
+                                    _ => { false } } }
+                            );
+                            if opens_body {
+                                verbatim_next = true }
+                            else {
+                                // The line after the region gets a separator like after any
+                                // other line, unless the region ends with one
+                                handle_tts(psess, last_pos, file, comments, config,
+                                           &rest[..region_len], (0, BlockFlag::Verbatim));
+                                for _ in 0..region_len {
+                                    region_end = iter.next() };
+                                attr_state = AttrState::None;
+                                last_line = last_pos.0 } } },
+                    // The body of a verbatim macro is every token tree up until the first
+                    // line which isn't indented past the line the opener is on
+                    _ if verbatim_next && opens_block(psess, config, indent_stack.last().unwrap().1,
+                                                      tok, span, iter.peek(), colon_state) => {
                         write!(file, " {{").unwrap();
+                        let (arrow_line, _, _, _) = ends_from_span(psess, config, span);
+                        let loc = psess.codemap().lookup_char_pos(span.lo);
+                        let arrow_indent = line_indent(config, loc.file.get_line(arrow_line).unwrap());
+                        let rest = &tts[tts.len() - iter.clone().count()..];
+                        let body_len = verbatim_len(psess, config, rest, arrow_line, arrow_indent);
+                        handle_tts(psess, last_pos, file, comments, config, &rest[..body_len],
+                                   (0, BlockFlag::Verbatim));
+                        for _ in 0..body_len {
+                            iter.next();
+                            () };
+                        // Like an empty block, the next line closes the body
+                        indent_stack.push((usize::MAX, BlockFlag::Macro));
+                        item_starts.push(item_start);
+                        verbatim_next = false;
+                        next_block_flag = BlockFlag::None;
+                        saw_block_keyword = false;
+                        colon_state = ColonState::Opener },
+                    // The string is a single token, so the lines inside of it never
+                    // count as indentation
+                    Token::Ident(ref id, IdentStyle::Plain)
+                            if id.as_str() == "dedent" &&
+                               indent_stack.last().unwrap().1 != BlockFlag::Verbatim &&
+                               iter.peek().map_or(false, |tt| is_literal(tt)) => {
+                        if let Some(&TtToken(lit_span, ref lit)) = iter.next() {
+                            let text = dedent_str_lit(config, lit).unwrap_or_else(|| {
+                                psess.span_diagnostic.span_err(lit_span, "only string literals can be dedented");
+                                pprust::token_to_string(lit) }
+                            );
+                            print_with_span(psess, config, last_pos, file, comments, &text,
+                                            mk_sp(span.lo, lit_span.hi));
+                            last_line = last_pos.0 } },
+                    _ if opens_block(psess, config, indent_stack.last().unwrap().1,
+                                     tok, span, iter.peek(), colon_state) => {
+                        // Checked before the flag is set for blocks nested in items, like
+                        // enum variants, which are headed by a single line
+                        let is_item_head = is_item_block(next_block_flag);
+                        match indent_stack.last().unwrap().1 {
+                            // Match statements and macro rules actually need the fat arrows
+                            // to be written to the output to function - so we write them out.
+                            BlockFlag::Match | BlockFlag::MacroRules => {
+                                print_with_span(psess, config, last_pos, file, comments, "=>", span) },
+                            // Enum variants with named fields are opened with `Name =>`, and
+                            // their fields are laid out like the fields of a struct
+                            BlockFlag::EnumStruct if next_block_flag == BlockFlag::None => {
+                                next_block_flag = BlockFlag::EnumStruct },
+                            // Groups nested in a use tree are use trees too
+                            BlockFlag::Use if next_block_flag == BlockFlag::None => {
+                                next_block_flag = BlockFlag::Use },
+                            // `name! =>` is a macro invocation with a body full of items
+                            _ if next_block_flag == BlockFlag::None && is_macro_bang(prev_tt) => {
+                                next_block_flag = BlockFlag::Macro },
+                            // Outside of the head of an item or control flow expression,
+                            // `Path =>` is a struct literal with one field on each line
+                            _ if next_block_flag == BlockFlag::None && !saw_block_keyword &&
+                                    is_path_end(prev_tt) => {
+                                next_block_flag = BlockFlag::StructLit },
+                            _ => { () } };
+                        // Create the block! Use trees are glued onto the end of their path.
+                        if next_block_flag == BlockFlag::Use {
+                            write!(file, "::{{").unwrap() }
+                        else {
+                            write!(file, " {{").unwrap() };
+                        // `...` is a placeholder for a block which is meant to be empty
+                        let placeholder = match iter.peek() {
+                            Some(&&TtToken(_, Token::DotDotDot)) => { true },
+                            _ => { false } };
+                        if placeholder {
+                            iter.next();
+                            () };
                         match iter.peek() {
                             None => {
                                 write!(file, " }}").unwrap() },
                             Some(tt) => {
-                                let (_, fcol, lline, _) = ends_from_span(psess, tt.get_span());
-                                indent_stack.push((fcol, next_block_flag));
+                                let (arrow_line, _, _, _) = ends_from_span(psess, config, span);
+                                let (fline, fcol, lline, _) = ends_from_span(psess, config, tt.get_span());
+                                // The body of a block has to be indented further than both the
+                                // block it is in and the line its head starts on. The head of
+                                // an item can go on over several lines, and starts on the line
+                                // with its keyword, while anything else starts on the line the
+                                // `=>` is on.
+                                let head_indent = if is_item_head {
+                                    item_indent }
+                                else {
+                                    pos_indent(psess, config, span.lo) };
+                                let old_indent = cmp::min(indent_stack.last().unwrap().0, head_indent);
+                                if placeholder || (fline > arrow_line && fcol <= old_indent) {
+                                    // The block is empty, so the next line closes it again.
+                                    // No line can be indented this far, so it will be popped.
+                                    indent_stack.push((usize::MAX, next_block_flag));
+                                    item_starts.push(item_start) }
+                                else {
+                                    // This line is skipped by the checks for new lines
+                                    if fline > arrow_line {
+                                        check_indent_chars(psess, config, tt.get_span(), Some(span)) };
+                                    match config.indent {
+                                        Some(indent) if fline > arrow_line &&
+                                                fcol != head_indent + indent => {
+                                            let msg = format!("expected a block indented by {} columns",
+                                                              indent);
+                                            psess.span_diagnostic.span_err(tt.get_span(), &msg) },
+                                        _ => { () } };
+                                    indent_stack.push((fcol, next_block_flag));
+                                    last_line = lline;
+                                    item_starts.push(item_start);
+                                    item_start = index + 1 };
                                 next_block_flag = BlockFlag::None;
-                                last_line = lline } } },
+                                saw_block_keyword = false;
+                                colon_state = ColonState::Opener } } },
                     _ => {
                         if let Token::Ident(ref id, IdentStyle::Plain) = *tok {
+                            let prev_flag = next_block_flag;
                             match id.as_str() {
                                 "match" => { next_block_flag = BlockFlag::Match },
                                 "struct" | "enum" => { next_block_flag = BlockFlag::EnumStruct },
                                 "mod" => { next_block_flag = BlockFlag::Module },
-                                _ => { () } } };
-                        print_with_span(psess, last_pos, file,
+                                "use" => { next_block_flag = BlockFlag::Use },
+                                "macro_rules" => { next_block_flag = BlockFlag::MacroRules },
+                                "trait" => { next_block_flag = BlockFlag::Trait },
+                                // `extern crate` never opens a block, and `extern fn` has a
+                                // normal body, so only a bare `extern "ABI" =>` is affected
+                                "extern" => { next_block_flag = BlockFlag::Extern },
+                                "fn" => { next_block_flag = BlockFlag::Fn },
+                                "if" | "while" | "for" | "loop" => { saw_block_keyword = true },
+                                "let" => { colon_state = ColonState::LetType },
+                                "where" => { colon_state = ColonState::WhereBounds },
+                                // `-> impl Trait` is a return type, and `impl Trait` can be an
+                                // argument type too, so neither is an impl block
+                                "impl" if next_block_flag == BlockFlag::Fn => { () },
+                                "impl" => {
+                                    if let Some(&TtToken(_, Token::RArrow)) = prev_tt {    () }
+                                    else {    next_block_flag = BlockFlag::Impl } },
+                                _ => { () } };
+                            if next_block_flag != prev_flag && is_item_block(next_block_flag) {
+                                item_indent = pos_indent(psess, config, span.lo) } };
+                        colon_state = match (colon_state, tok) {
+                            (ColonState::LetType, &Token::Colon) | (ColonState::LetType, &Token::Eq) => {
+                                ColonState::Opener },
+                            (ColonState::WhereBounds, &Token::Colon) => { ColonState::Where },
+                            (ColonState::Where, &Token::Comma) => { ColonState::WhereBounds },
+                            _ => { colon_state } };
+                        print_with_span(psess, config, last_pos, file, comments,
                                         &pprust::token_to_string(tok), span) } } },
             Some(&TtDelimited(_, ref delimited)) => {
                 let (opening, closing) = match delimited.delim {
                     DelimToken::Paren => { ("(", ")") },
                     DelimToken::Bracket => { ("[", "]") },
                     DelimToken::Brace => { ("{", "}") } };
-                print_with_span(psess, last_pos, file, opening, delimited.open_span);
-                handle_tts(psess, last_pos, file, &delimited.tts);
-                print_with_span(psess, last_pos, file, closing, delimited.close_span) },
+                print_with_span(psess, config, last_pos, file, comments, opening, delimited.open_span);
+                let block_flag = indent_stack.last().unwrap().1;
+                // Repetitions like `$($x),*` in macro bodies aren't parsed as sequences
+                // outside of a macro definition, so they show up as a `$` and parentheses
+                let repetition = match prev_tt {
+                    Some(&TtToken(_, Token::Dollar)) => { delimited.delim == DelimToken::Paren },
+                    _ => { false } };
+                match (delimited.delim, delimited.tts.first()) {
+                    // The patterns of macro rules, repetitions, and macro definitions which
+                    // are written in braces rather than laid out, are passed through untouched
+                    _ if block_flag == BlockFlag::MacroRules || block_flag == BlockFlag::Verbatim ||
+                            repetition || (next_block_flag == BlockFlag::MacroRules &&
+                                           delimited.delim == DelimToken::Brace) => {
+                        handle_tts(psess, last_pos, file, comments, config, &delimited.tts,
+                                   (0, BlockFlag::Verbatim)) },
+                    // `(=>` and `[=>` start a list, with one element on each line. The
+                    // column of the first element is the column of the whole list.
+                    (DelimToken::Paren, Some(&TtToken(_, ref tok))) |
+                        (DelimToken::Bracket, Some(&TtToken(_, ref tok)))
+                            if is_opener_token(config.opener, tok) => {
+                        if let Some(first) = delimited.tts.get(1) {
+                            let (_, fcol, _, _) = ends_from_span(psess, config, first.get_span());
+                            let elements = &delimited.tts[1..];
+                            handle_tts(psess, last_pos, file, comments, config, elements,
+                                       (fcol, BlockFlag::List));
+                            // A tuple with one element needs a comma after it, or it is
+                            // just that element in parentheses
+                            let trailing_comma = match elements.last() {
+                                Some(&TtToken(_, Token::Comma)) => { true },
+                                _ => { false } };
+                            if delimited.delim == DelimToken::Paren && !is_call(prev_tt) &&
+                                    !trailing_comma && list_len(psess, config, elements, fcol) == 1 {
+                                write!(file, ",").unwrap();
+                                last_pos.1 += 1 } } },
+                    _ => {
+                        // Blocks opened inside of the delimiters, such as closure bodies,
+                        // are laid out relative to the line the delimiters were opened on
+                        let loc = psess.codemap().lookup_char_pos(delimited.open_span.lo);
+                        let base_indent = line_indent(config, loc.file.get_line(loc.line - 1).unwrap());
+                        handle_tts(psess, last_pos, file, comments, config, &delimited.tts,
+                                   (base_indent, BlockFlag::Delimited)) } };
+                print_with_span(psess, config, last_pos, file, comments, closing, delimited.close_span) },
             Some(&TtSequence(..)) => { panic!("I don't think I should see this") },
-            None => { break } } };
-    for _ in 0..indent_stack.len() - 1 {
-        write!(file, " }}").unwrap() } }
\ No newline at end of file
+            None => { break } };
+        prev_tt = region_end.or(opt_tt) };
+    // Close any remaining blocks after we reach the end-of-block. The last line of
+    // the innermost block, which may be the whole file, can still need a separator.
+    if needs_item_semi(config, indent_stack.last().unwrap().1, prev_tt, &tts[item_start..], attr_state) {
+        write!(file, ";").unwrap() };
+    for _ in 1..indent_stack.len() {
+        let (_, block_flag) = *indent_stack.last().unwrap();
+        close_block(file, &mut indent_stack);
+        item_start = item_starts.pop().unwrap();
+        if needs_value_semi(indent_stack.last().unwrap().1, block_flag, &tts[item_start..]) {
+            write!(file, ";").unwrap() } } }
\ No newline at end of file